@@ -0,0 +1,415 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::OnceLock;
+
+/// The device every `adb` command targets, installed once at startup.
+static SERIAL: OnceLock<DeviceSerial> = OnceLock::new();
+
+/// The `-s <serial>` selection applied to every device-bound `adb` command.
+///
+/// Mirrors standard adb tooling: with no serial a single attached device is
+/// used implicitly, and more than one is an error the caller resolves by
+/// passing `-s`.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSerial(pub Option<String>);
+
+impl DeviceSerial {
+    /// Install the process-wide selection. Call once before any `adb` command.
+    pub fn install(self) {
+        let _ = SERIAL.set(self);
+    }
+
+    /// The installed serial, if any.
+    pub fn get() -> Option<&'static str> {
+        SERIAL.get().and_then(|d| d.0.as_deref())
+    }
+}
+
+/// Prepend `-s <serial>` to `cmd` when a device has been selected.
+fn with_serial(cmd: &mut Command) {
+    if let Some(serial) = DeviceSerial::get() {
+        cmd.args(["-s", serial]);
+    }
+}
+
+#[derive(Debug)]
+pub enum AdbErr {
+    IO(io::Error),
+    Adb(String),
+}
+
+impl fmt::Display for AdbErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdbErr::IO(e) => write!(f, "{e}"),
+            AdbErr::Adb(e) => write!(f, "{}", e.trim_end()),
+        }
+    }
+}
+
+impl std::error::Error for AdbErr {}
+
+impl From<io::Error> for AdbErr {
+    fn from(e: io::Error) -> Self {
+        AdbErr::IO(e)
+    }
+}
+
+/// Run a one-shot `adb` invocation and collect its stdout.
+///
+/// Prefer the [`adb_cmd!`](crate::adb_cmd) macro over calling this directly.
+pub fn adb_run<const N: usize>(args: [&str; N]) -> Result<String, AdbErr> {
+    let mut cmd = Command::new("adb");
+    with_serial(&mut cmd);
+    let out = cmd.args(args).output()?;
+    if !out.status.success() {
+        return Err(AdbErr::Adb(String::from_utf8_lossy(&out.stderr).into_owned()));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Build and run an `adb` command, returning its stdout as a `String`.
+///
+/// ```ignore
+/// let devices = adb_cmd!("devices")?;
+/// adb_cmd!("pull", "-a", src, dst)?;
+/// ```
+#[macro_export]
+macro_rules! adb_cmd {
+    ($($arg:expr),+ $(,)?) => {{
+        $crate::adb::adb_run([$($arg),+])
+    }};
+}
+
+/// A string printed by the device shell after every command so we know where
+/// one command's output ends and the next begins.
+const SENTINEL: &str = "___ADB_SINK_DONE___";
+
+/// A long-lived `adb shell` process.
+///
+/// Spawning one `adb shell` per directory walk is expensive, so we keep a
+/// single interactive shell open and feed it commands, reading back each
+/// command's output up to [`SENTINEL`].
+pub struct AdbShell {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl AdbShell {
+    pub fn new() -> Result<Self, AdbErr> {
+        let mut cmd = Command::new("adb");
+        with_serial(&mut cmd);
+        let mut child = cmd
+            .arg("shell")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Run `cmd` in the device shell and return everything it prints.
+    pub fn exec(&mut self, cmd: &str) -> Result<String, AdbErr> {
+        writeln!(self.stdin, "{cmd}; echo {SENTINEL}")?;
+        self.stdin.flush()?;
+        let mut out = String::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line)? == 0 {
+                break;
+            }
+            if line.trim_end() == SENTINEL {
+                break;
+            }
+            out.push_str(&line);
+        }
+        Ok(out)
+    }
+}
+
+/// Escape `path` so it survives word-splitting and expansion in the device
+/// shell.
+///
+/// Everything outside the portable-filename set `[A-Za-z0-9_@%+=:,./-]` is
+/// backslash-escaped, which covers spaces, shell metacharacters and quotes
+/// that turn up routinely under `/sdcard/`. Paths handed to `adb pull`/`push`
+/// as separate argv entries do not need this; only strings interpolated into
+/// an `adb shell` command line do.
+pub fn shell_quote(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, 'A'..='Z' | 'a'..='z' | '0'..='9')
+            || matches!(c, '_' | '@' | '%' | '+' | '=' | ':' | ',' | '.' | '/' | '-')
+        {
+            out.push(c);
+        } else {
+            out.push('\\');
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Address of the local adb server.
+const ADB_SERVER: &str = "127.0.0.1:5037";
+/// Largest payload a single `DATA` packet may carry.
+const SYNC_DATA_MAX: usize = 64 * 1024;
+
+/// The 8-byte sync sub-command framing: a 4-byte id and a little-endian length.
+fn sync_frame(id: &[u8; 4], len: u32) -> [u8; 8] {
+    let mut buf = [0u8; 8];
+    buf[..4].copy_from_slice(id);
+    buf[4..].copy_from_slice(&len.to_le_bytes());
+    buf
+}
+
+/// A mode/size/mtime triple as reported by the sync service's `STAT`/`LIST`.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncStat {
+    pub mode: u32,
+    pub size: u32,
+    pub mtime: i64,
+}
+
+impl SyncStat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+    pub fn is_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+    pub fn exists(&self) -> bool {
+        self.mode != 0
+    }
+}
+
+/// A single `LIST` entry: a [`SyncStat`] plus the entry's leaf name.
+#[derive(Debug, Clone)]
+pub struct SyncDent {
+    pub stat: SyncStat,
+    pub name: String,
+}
+
+/// A connection to the adb server speaking the native sync protocol.
+///
+/// This talks the same wire format as `adb push`/`adb pull` but in-process, so
+/// a full tree sync costs a handful of round-trips instead of one process spawn
+/// per file. Construct one with [`SyncClient::connect`]; when the server is
+/// unreachable the caller falls back to spawning the `adb` binary.
+pub struct SyncClient {
+    stream: TcpStream,
+}
+
+impl SyncClient {
+    /// Bind to `serial` and switch the stream into sync mode.
+    pub fn connect(serial: &str) -> Result<Self, AdbErr> {
+        let stream = TcpStream::connect(ADB_SERVER)?;
+        let mut client = Self { stream };
+        client.host_request(&format!("host:transport:{serial}"))?;
+        client.host_request("sync:")?;
+        Ok(client)
+    }
+
+    /// Send a host request (`<4-hex-len><payload>`) and check the status word.
+    fn host_request(&mut self, payload: &str) -> Result<(), AdbErr> {
+        write!(self.stream, "{:04x}", payload.len())?;
+        self.stream.write_all(payload.as_bytes())?;
+        self.stream.flush()?;
+        let mut status = [0u8; 4];
+        self.stream.read_exact(&mut status)?;
+        if &status != b"OKAY" {
+            let mut len = [0u8; 4];
+            self.stream.read_exact(&mut len)?;
+            let mut msg = vec![0u8; u16::from_str_radix(std::str::from_utf8(&len[..4]).unwrap_or("0000"), 16).unwrap_or(0) as usize];
+            let _ = self.stream.read_exact(&mut msg);
+            return Err(AdbErr::Adb(String::from_utf8_lossy(&msg).into_owned()));
+        }
+        Ok(())
+    }
+
+    /// Write an 8-byte sync sub-command: a 4-byte id and a little-endian length.
+    fn send_cmd(&mut self, id: &[u8; 4], len: u32) -> io::Result<()> {
+        self.stream.write_all(&sync_frame(id, len))
+    }
+
+    fn read_id(&mut self) -> io::Result<[u8; 4]> {
+        let mut id = [0u8; 4];
+        self.stream.read_exact(&mut id)?;
+        Ok(id)
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.stream.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// `STAT` a single remote path.
+    pub fn stat(&mut self, path: &str) -> Result<SyncStat, AdbErr> {
+        self.send_cmd(b"STAT", path.len() as u32)?;
+        self.stream.write_all(path.as_bytes())?;
+        self.stream.flush()?;
+        let id = self.read_id()?;
+        if &id != b"STAT" {
+            return Err(AdbErr::Adb(format!("unexpected stat reply: {id:?}")));
+        }
+        let mode = self.read_u32()?;
+        let size = self.read_u32()?;
+        let mtime = self.read_u32()?;
+        Ok(SyncStat {
+            mode,
+            size,
+            mtime: mtime as i64,
+        })
+    }
+
+    /// `LIST` the immediate children of a remote directory.
+    pub fn list(&mut self, path: &str) -> Result<Vec<SyncDent>, AdbErr> {
+        self.send_cmd(b"LIST", path.len() as u32)?;
+        self.stream.write_all(path.as_bytes())?;
+        self.stream.flush()?;
+        let mut dents = Vec::new();
+        loop {
+            let id = self.read_id()?;
+            match &id {
+                b"DENT" => {
+                    let mode = self.read_u32()?;
+                    let size = self.read_u32()?;
+                    let mtime = self.read_u32()?;
+                    let name_len = self.read_u32()? as usize;
+                    let mut name = vec![0u8; name_len];
+                    self.stream.read_exact(&mut name)?;
+                    dents.push(SyncDent {
+                        stat: SyncStat {
+                            mode,
+                            size,
+                            mtime: mtime as i64,
+                        },
+                        name: String::from_utf8_lossy(&name).into_owned(),
+                    });
+                }
+                b"DONE" => {
+                    // DONE is followed by a (here irrelevant) stat trailer.
+                    let mut rest = [0u8; 12];
+                    self.stream.read_exact(&mut rest)?;
+                    break;
+                }
+                _ => return Err(AdbErr::Adb(format!("unexpected list reply: {id:?}"))),
+            }
+        }
+        Ok(dents)
+    }
+
+    /// `SEND` `data` to `remote` with the given unix `mode` and `mtime`.
+    pub fn push(&mut self, remote: &str, mode: u32, mtime: i64, mut data: impl Read) -> Result<(), AdbErr> {
+        let spec = format!("{remote},{mode}");
+        self.send_cmd(b"SEND", spec.len() as u32)?;
+        self.stream.write_all(spec.as_bytes())?;
+        let mut buf = [0u8; SYNC_DATA_MAX];
+        loop {
+            let n = data.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            self.send_cmd(b"DATA", n as u32)?;
+            self.stream.write_all(&buf[..n])?;
+        }
+        self.send_cmd(b"DONE", mtime as u32)?;
+        self.stream.flush()?;
+        let id = self.read_id()?;
+        let _status_len = self.read_u32()?;
+        if &id != b"OKAY" {
+            // The FAIL message body is left unread on purpose: the caller drops
+            // the connection on error, so the stream is never reused.
+            return Err(AdbErr::Adb("push rejected by device".into()));
+        }
+        Ok(())
+    }
+
+    /// `RECV` `remote` into `out`.
+    pub fn pull(&mut self, remote: &str, mut out: impl Write) -> Result<(), AdbErr> {
+        self.send_cmd(b"RECV", remote.len() as u32)?;
+        self.stream.write_all(remote.as_bytes())?;
+        self.stream.flush()?;
+        loop {
+            let id = self.read_id()?;
+            match &id {
+                b"DATA" => {
+                    let len = self.read_u32()? as usize;
+                    let mut buf = vec![0u8; len];
+                    self.stream.read_exact(&mut buf)?;
+                    out.write_all(&buf)?;
+                }
+                b"DONE" => {
+                    let _ = self.read_u32()?;
+                    break;
+                }
+                b"FAIL" => {
+                    let len = self.read_u32()? as usize;
+                    let mut msg = vec![0u8; len];
+                    self.stream.read_exact(&mut msg)?;
+                    return Err(AdbErr::Adb(String::from_utf8_lossy(&msg).into_owned()));
+                }
+                _ => return Err(AdbErr::Adb(format!("unexpected recv reply: {id:?}"))),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shell_quote, sync_frame, SyncStat};
+
+    #[test]
+    fn sync_frame_is_id_plus_le_length() {
+        assert_eq!(sync_frame(b"DATA", 0), [b'D', b'A', b'T', b'A', 0, 0, 0, 0]);
+        // 0x0001_e240 == 123456, little-endian.
+        assert_eq!(
+            sync_frame(b"DONE", 123456),
+            [b'D', b'O', b'N', b'E', 0x40, 0xe2, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn sync_stat_classifies_mode() {
+        let dir = SyncStat { mode: 0o040755, size: 0, mtime: 0 };
+        let file = SyncStat { mode: 0o100644, size: 0, mtime: 0 };
+        let none = SyncStat { mode: 0, size: 0, mtime: 0 };
+        assert!(dir.is_dir() && !dir.is_file() && dir.exists());
+        assert!(file.is_file() && !file.is_dir() && file.exists());
+        assert!(!none.exists());
+    }
+
+    #[test]
+    fn leaves_safe_paths_untouched() {
+        assert_eq!(shell_quote("/sdcard/DCIM/IMG_0001.jpg"), "/sdcard/DCIM/IMG_0001.jpg");
+    }
+
+    #[test]
+    fn escapes_spaces() {
+        assert_eq!(shell_quote("/sdcard/My Photos/a b.png"), "/sdcard/My\\ Photos/a\\ b.png");
+    }
+
+    #[test]
+    fn escapes_shell_metacharacters() {
+        assert_eq!(
+            shell_quote("/sdcard/x($y)&'z\""),
+            "/sdcard/x\\(\\$y\\)\\&\\'z\\\""
+        );
+    }
+
+    #[test]
+    fn escapes_unicode() {
+        assert_eq!(shell_quote("/sdcard/音楽/歌.mp3"), "/sdcard/\\音\\楽/\\歌.mp3");
+    }
+}
@@ -0,0 +1,453 @@
+use crate::adb::{shell_quote, AdbShell, SyncClient};
+use crate::adb_cmd;
+use std::hash::{Hash, Hasher};
+use typed_path::{UnixPath, UnixPathBuf};
+
+/// Borrow a `UnixPath` as `&str`.
+///
+/// Every path in this tool originates from a UTF-8 source (a local path we
+/// converted or a line of `adb` output), so the conversion is infallible in
+/// practice.
+pub trait AsStr {
+    fn as_str(&self) -> &str;
+}
+
+impl AsStr for UnixPath {
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(self.as_bytes()).expect("path is valid utf8")
+    }
+}
+
+/// A single file (or directory) discovered on either side of a sync.
+///
+/// Equality and hashing are by `name` only: the file maps are keyed by the
+/// directory a file lives in, so within one directory the name uniquely
+/// identifies a file regardless of which side it came from.
+#[derive(Debug, Clone)]
+pub struct SyncFile {
+    pub path: UnixPathBuf,
+    pub name: Box<str>,
+    pub size: u64,
+    pub timestamp: i64,
+}
+
+impl PartialEq for SyncFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+impl Eq for SyncFile {}
+impl Hash for SyncFile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
+}
+
+impl SyncFile {
+    fn new(path: UnixPathBuf, size: u64, timestamp: i64) -> Self {
+        let name = path
+            .file_name()
+            .map(|n| String::from_utf8_lossy(n).into_owned())
+            .unwrap_or_default()
+            .into_boxed_str();
+        Self {
+            path,
+            name,
+            size,
+            timestamp,
+        }
+    }
+}
+
+/// The set of operations `pull_push` needs from either end of a transfer.
+pub trait FileSystem {
+    /// Return `(files, empty_dirs)` found recursively under `path`.
+    fn get_all_files(&mut self, path: &UnixPath) -> anyhow::Result<(Vec<SyncFile>, Vec<SyncFile>)>;
+    fn mkdir(&mut self, path: &UnixPath) -> anyhow::Result<()>;
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: i64) -> anyhow::Result<()>;
+    fn rm_file(&mut self, path: &UnixPath) -> anyhow::Result<()>;
+    fn rm_dir(&mut self, path: &UnixPath) -> anyhow::Result<()>;
+
+    /// The MD5 digest of `path`'s contents, used by `--checksum` to catch files
+    /// that differ without a size or mtime change.
+    fn digest(&mut self, path: &UnixPath) -> anyhow::Result<[u8; 16]>;
+
+    /// Stat a single file. Used by `--verify` to confirm a push landed intact;
+    /// the device side flushes buffered writes to storage first.
+    fn stat(&mut self, path: &UnixPath) -> anyhow::Result<SyncFile>;
+
+    /// Carry out a single `adb_command` (`"pull"` or `"push"`) moving `src` to
+    /// `dst`, and return a human-readable status line.
+    ///
+    /// The default spawns the `adb` binary once per file; [`AndroidFS`]
+    /// overrides it to use the in-process sync protocol when available.
+    fn transfer(
+        &mut self,
+        adb_command: &str,
+        src: &UnixPath,
+        dst: &UnixPath,
+        set_times: bool,
+    ) -> anyhow::Result<String> {
+        let op = if set_times {
+            adb_cmd!(adb_command, "-a", src.as_str(), dst.as_str())?
+        } else {
+            adb_cmd!(adb_command, src.as_str(), dst.as_str())?
+        };
+        Ok(op)
+    }
+}
+
+/// The local filesystem.
+pub struct LocalFS;
+
+impl LocalFS {
+    fn walk(
+        dir: &std::path::Path,
+        root: &UnixPath,
+        files: &mut Vec<SyncFile>,
+        empty_dirs: &mut Vec<SyncFile>,
+    ) -> anyhow::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?.peekable();
+        if entries.peek().is_none() && dir != AsRef::<std::path::Path>::as_ref(root.as_str()) {
+            empty_dirs.push(SyncFile::new(to_unix(dir), 0, 0));
+            return Ok(());
+        }
+        for entry in entries {
+            let entry = entry?;
+            let md = entry.metadata()?;
+            let path = entry.path();
+            if md.is_dir() {
+                LocalFS::walk(&path, root, files, empty_dirs)?;
+            } else {
+                let ts = md
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0);
+                files.push(SyncFile::new(to_unix(&path), md.len(), ts));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_unix(p: &std::path::Path) -> UnixPathBuf {
+    typed_path::PathBuf::<typed_path::NativeEncoding>::try_from(p.to_path_buf())
+        .expect("native path")
+        .with_unix_encoding()
+}
+
+fn to_native(p: &UnixPath) -> std::path::PathBuf {
+    std::path::PathBuf::from(p.as_str())
+}
+
+impl FileSystem for LocalFS {
+    fn get_all_files(&mut self, path: &UnixPath) -> anyhow::Result<(Vec<SyncFile>, Vec<SyncFile>)> {
+        let mut files = Vec::new();
+        let mut empty_dirs = Vec::new();
+        let native = to_native(path);
+        if native.exists() {
+            LocalFS::walk(&native, path, &mut files, &mut empty_dirs)?;
+        }
+        Ok((files, empty_dirs))
+    }
+
+    fn mkdir(&mut self, path: &UnixPath) -> anyhow::Result<()> {
+        std::fs::create_dir_all(to_native(path))?;
+        Ok(())
+    }
+
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: i64) -> anyhow::Result<()> {
+        let mtime = filetime::FileTime::from_unix_time(timestamp, 0);
+        filetime::set_file_mtime(to_native(path), mtime)?;
+        Ok(())
+    }
+
+    fn rm_file(&mut self, path: &UnixPath) -> anyhow::Result<()> {
+        std::fs::remove_file(to_native(path))?;
+        Ok(())
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> anyhow::Result<()> {
+        std::fs::remove_dir_all(to_native(path))?;
+        Ok(())
+    }
+
+    fn digest(&mut self, path: &UnixPath) -> anyhow::Result<[u8; 16]> {
+        let bytes = std::fs::read(to_native(path))?;
+        Ok(md5::compute(bytes).into())
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> anyhow::Result<SyncFile> {
+        let md = std::fs::metadata(to_native(path))?;
+        let ts = md
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Ok(SyncFile::new(path.to_path_buf(), md.len(), ts))
+    }
+}
+
+/// Parse the leading hex field of `md5sum` output into a digest.
+fn parse_md5_hex(out: &str) -> anyhow::Result<[u8; 16]> {
+    let hex = out
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("empty md5sum output"))?;
+    if hex.len() != 32 {
+        anyhow::bail!("unexpected md5sum output: '{out}'");
+    }
+    let mut digest = [0u8; 16];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(digest)
+}
+
+/// A device reached over `adb`.
+///
+/// A persistent `shell` is always available; `sync` holds an in-process sync
+/// connection when the adb server could be reached, and falls back to the
+/// `shell`/binary path when it is `None`.
+pub struct AndroidFS {
+    pub shell: AdbShell,
+    pub sync: Option<SyncClient>,
+}
+
+impl AndroidFS {
+    /// Recursively collect files under `dir` using the sync `LIST` command.
+    fn sync_walk(
+        sync: &mut SyncClient,
+        dir: &UnixPath,
+        files: &mut Vec<SyncFile>,
+        empty_dirs: &mut Vec<SyncFile>,
+    ) -> anyhow::Result<()> {
+        let dents = sync.list(dir.as_str())?;
+        let children: Vec<_> = dents
+            .into_iter()
+            .filter(|d| d.name != "." && d.name != "..")
+            .collect();
+        if children.is_empty() {
+            empty_dirs.push(SyncFile::new(dir.to_path_buf(), 0, 0));
+            return Ok(());
+        }
+        for d in children {
+            let path = dir.join(&d.name);
+            if d.stat.is_dir() {
+                AndroidFS::sync_walk(sync, &path, files, empty_dirs)?;
+            } else {
+                files.push(SyncFile::new(path, d.stat.size as u64, d.stat.mtime));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse `stat`/`find` output lines of the form `"<size> <mtime> <path>"`.
+    fn parse_stat_lines(out: &str) -> Vec<SyncFile> {
+        let mut files = Vec::new();
+        for line in out.lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let mut it = line.splitn(3, ' ');
+            let (Some(size), Some(ts), Some(path)) = (it.next(), it.next(), it.next()) else {
+                continue;
+            };
+            let (Ok(size), Ok(ts)) = (size.parse::<u64>(), ts.parse::<i64>()) else {
+                continue;
+            };
+            files.push(SyncFile::new(UnixPathBuf::from(path), size, ts));
+        }
+        files
+    }
+}
+
+impl FileSystem for AndroidFS {
+    fn get_all_files(&mut self, path: &UnixPath) -> anyhow::Result<(Vec<SyncFile>, Vec<SyncFile>)> {
+        if let Some(sync) = self.sync.as_mut() {
+            let mut files = Vec::new();
+            let mut empty_dirs = Vec::new();
+            AndroidFS::sync_walk(sync, path, &mut files, &mut empty_dirs)?;
+            return Ok((files, empty_dirs));
+        }
+        let p = shell_quote(path.as_str());
+        let files = AndroidFS::parse_stat_lines(&self.shell.exec(&format!(
+            "find {p} -type f -exec stat -c '%s %Y %n' {{}} +"
+        ))?);
+        let empty_dirs = self
+            .shell
+            .exec(&format!("find {p} -type d -empty"))?
+            .lines()
+            .map(|l| l.trim_end())
+            .filter(|l| !l.is_empty())
+            .map(|l| SyncFile::new(UnixPathBuf::from(l), 0, 0))
+            .collect();
+        Ok((files, empty_dirs))
+    }
+
+    fn mkdir(&mut self, path: &UnixPath) -> anyhow::Result<()> {
+        self.shell
+            .exec(&format!("mkdir -p {}", shell_quote(path.as_str())))?;
+        Ok(())
+    }
+
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: i64) -> anyhow::Result<()> {
+        self.shell.exec(&format!(
+            "touch -d @{timestamp} {}",
+            shell_quote(path.as_str())
+        ))?;
+        Ok(())
+    }
+
+    fn rm_file(&mut self, path: &UnixPath) -> anyhow::Result<()> {
+        self.shell
+            .exec(&format!("rm -f {}", shell_quote(path.as_str())))?;
+        Ok(())
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> anyhow::Result<()> {
+        self.shell
+            .exec(&format!("rm -rf {}", shell_quote(path.as_str())))?;
+        Ok(())
+    }
+
+    fn digest(&mut self, path: &UnixPath) -> anyhow::Result<[u8; 16]> {
+        let out = self
+            .shell
+            .exec(&format!("md5sum {}", shell_quote(path.as_str())))?;
+        parse_md5_hex(&out)
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> anyhow::Result<SyncFile> {
+        // Flush buffered writes to storage before trusting the stat.
+        self.shell.exec("sync")?;
+        // Use the shell `stat` rather than the sync protocol's `STAT`, whose v1
+        // `size` is a u32 and would truncate (and spuriously fail `--verify`
+        // on) files >= 4 GiB.
+        let out = self.shell.exec(&format!(
+            "stat -c '%s %Y' {}",
+            shell_quote(path.as_str())
+        ))?;
+        let mut it = out.split_whitespace();
+        let (Some(size), Some(ts)) = (it.next(), it.next()) else {
+            anyhow::bail!("could not stat '{}'", path.as_str());
+        };
+        Ok(SyncFile::new(
+            path.to_path_buf(),
+            size.parse()?,
+            ts.parse()?,
+        ))
+    }
+
+    fn transfer(
+        &mut self,
+        adb_command: &str,
+        src: &UnixPath,
+        dst: &UnixPath,
+        set_times: bool,
+    ) -> anyhow::Result<String> {
+        // Fast path: speak the sync protocol directly over TCP.
+        if self.sync.is_some() {
+            let res: anyhow::Result<()> = (|| {
+                let sync = self.sync.as_mut().expect("checked above");
+                match adb_command {
+                    "push" => {
+                        let local = to_native(src);
+                        let md = std::fs::metadata(&local)?;
+                        let mode = file_mode(&md);
+                        // Always stamp the real local mtime (as `adb push` does)
+                        // so size-equal files stay idempotent across runs; `-t`
+                        // only governs the local/pull side.
+                        let mtime = unix_mtime(&md);
+                        let f = std::fs::File::open(&local)?;
+                        sync.push(dst.as_str(), mode, mtime, f)?;
+                    }
+                    "pull" => {
+                        let f = std::fs::File::create(to_native(dst))?;
+                        sync.pull(src.as_str(), f)?;
+                        // Unlike the `adb pull -a` fallback, RECV carries no
+                        // mtime, so preserve it explicitly from the remote STAT.
+                        if set_times {
+                            let st = sync.stat(src.as_str())?;
+                            let mtime = filetime::FileTime::from_unix_time(st.mtime, 0);
+                            filetime::set_file_mtime(to_native(dst), mtime)?;
+                        }
+                    }
+                    other => anyhow::bail!("unknown adb command: {other}"),
+                }
+                Ok(())
+            })();
+            match res {
+                Ok(()) => return Ok(format!("{} -> {}", src.as_str(), dst.as_str())),
+                // A protocol error can leave the stream mid-frame; drop the
+                // connection so the rest of the run uses the binary cleanly.
+                Err(e) => {
+                    crate::log!("sync protocol failed ({e}), falling back to adb binary");
+                    self.sync = None;
+                }
+            }
+        }
+        let op = if set_times {
+            adb_cmd!(adb_command, "-a", src.as_str(), dst.as_str())?
+        } else {
+            adb_cmd!(adb_command, src.as_str(), dst.as_str())?
+        };
+        Ok(op)
+    }
+}
+
+/// The unix mode of `md` (file type and permission bits), defaulting to a
+/// regular file with `0o644` permissions (`0o100644`) off-unix.
+fn file_mode(md: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        md.mode()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = md;
+        0o100644
+    }
+}
+
+fn unix_mtime(md: &std::fs::Metadata) -> i64 {
+    md.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_md5_hex;
+
+    #[test]
+    fn parses_md5sum_output() {
+        let out = "d41d8cd98f00b204e9800998ecf8427e  /sdcard/x";
+        let digest = parse_md5_hex(out).unwrap();
+        assert_eq!(
+            digest,
+            [
+                0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8,
+                0x42, 0x7e
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_short_hash() {
+        assert!(parse_md5_hex("dead  /sdcard/x").is_err());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(parse_md5_hex("").is_err());
+        assert!(parse_md5_hex("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz  x").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use adb_sink::adb::AdbErr;
 use adb_sink::adb::AdbShell;
+use adb_sink::adb::DeviceSerial;
 use adb_sink::adb_cmd;
 use adb_sink::fs::AsStr;
 use adb_sink::fs::{AndroidFS, FileSystem, LocalFS, SyncFile};
@@ -7,12 +8,13 @@ use adb_sink::log;
 use clap::{Args, Parser, Subcommand};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::str::FromStr;
+use std::time::Duration;
 use typed_path::{UnixPath, UnixPathBuf};
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 #[command(arg_required_else_help(true))]
 struct PullPushArgs {
     source: PathBuf,
@@ -29,12 +31,22 @@ struct PullPushArgs {
     /// ignore dirs starting with specified string
     #[arg(short, long)]
     ignore_dir: Vec<Box<str>>,
+
+    /// compare files of equal size by content hash and transfer on mismatch
+    #[arg(short = 'c', long)]
+    checksum: bool,
+
+    /// after each push, re-stat the file on the device and fail on a mismatch
+    #[arg(long)]
+    verify: bool,
 }
 
 #[derive(Debug, Subcommand)]
 enum SubCmds {
     Pull(PullPushArgs),
     Push(PullPushArgs),
+    /// push the initial tree, then mirror local changes to the device live
+    Watch(PullPushArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -45,6 +57,10 @@ enum SubCmds {
 #[command(arg_required_else_help(true))]
 #[clap(version = "1.0", author = "github.com/j-hc")]
 struct Cli {
+    /// target device by serial (as listed by `adb devices`)
+    #[arg(short = 's', long, global = true)]
+    serial: Option<String>,
+
     #[clap(subcommand)]
     subcmd: SubCmds,
 }
@@ -71,25 +87,54 @@ enum SetMtime {
     None,
 }
 
-fn adb_with_reason(
+fn adb_with_reason<SRC: FileSystem, DEST: FileSystem>(
     adb_command: &str,
     af: &SyncFile,
     lf_path: &UnixPath,
     reason: &str,
     set_mtime: SetMtime,
-    dest_fs: &mut impl FileSystem,
+    verify: bool,
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
 ) -> anyhow::Result<()> {
-    let lf_str = lf_path.as_str();
-    let af_str = af.path.as_str();
+    // The device-side filesystem owns the transfer (it has the adb connection):
+    // for a pull that is the source, for a push the destination.
     let op = match set_mtime {
-        SetMtime::WithAdb => adb_cmd!(adb_command, "-a", af_str, lf_str)?,
+        SetMtime::WithAdb => {
+            src_fs.transfer(adb_command, &af.path, lf_path, true)?
+        }
         SetMtime::WithMtime => {
-            let op = adb_cmd!(adb_command, af_str, lf_str)?;
+            let op = dest_fs.transfer(adb_command, &af.path, lf_path, false)?;
             dest_fs.set_mtime(lf_path, af.timestamp)?;
             op
         }
-        SetMtime::None => adb_cmd!(adb_command, af_str, lf_str)?,
+        SetMtime::None => {
+            if adb_command == "pull" {
+                src_fs.transfer(adb_command, &af.path, lf_path, false)?
+            } else {
+                dest_fs.transfer(adb_command, &af.path, lf_path, false)?
+            }
+        }
     };
+    if verify && adb_command == "push" {
+        let got = dest_fs.stat(lf_path)?;
+        if got.size != af.size {
+            anyhow::bail!(
+                "verify failed for '{}': size {} on device, expected {}",
+                lf_path.display(),
+                got.size,
+                af.size
+            );
+        }
+        if matches!(set_mtime, SetMtime::WithMtime) && got.timestamp != af.timestamp {
+            anyhow::bail!(
+                "verify failed for '{}': mtime {} on device, expected {}",
+                lf_path.display(),
+                got.timestamp,
+                af.timestamp
+            );
+        }
+    }
     log!("{adb_command} ({reason}) {}", op.trim_end());
     Ok(())
 }
@@ -103,6 +148,8 @@ fn pull_push<SRC: FileSystem, DEST: FileSystem>(
         delete_if_dne,
         ignore_dir,
         set_times,
+        checksum,
+        verify,
     }: PullPushArgs,
     adb_command: &'static str,
 ) -> anyhow::Result<()> {
@@ -158,11 +205,19 @@ fn pull_push<SRC: FileSystem, DEST: FileSystem>(
         for af in &androidfs {
             let lf = localfs.as_ref().and_then(|localfs| localfs.get(af));
             match lf {
-                Some(lf) if af.size != lf.size => {
-                    adb_with_reason(adb_command, af, &lf.path, "SIZE", setmtime, dest_fs)?
-                }
-                Some(lf) if af.timestamp > lf.timestamp => {
-                    adb_with_reason(adb_command, af, &lf.path, "NEWER", setmtime, dest_fs)?
+                Some(lf) if af.size != lf.size => adb_with_reason(
+                    adb_command, af, &lf.path, "SIZE", setmtime, verify, src_fs, dest_fs,
+                )?,
+                Some(lf) if af.timestamp > lf.timestamp => adb_with_reason(
+                    adb_command, af, &lf.path, "NEWER", setmtime, verify, src_fs, dest_fs,
+                )?,
+                // Sizes are equal here; only then is hashing both sides worth it.
+                Some(lf) if checksum => {
+                    if src_fs.digest(&af.path)? != dest_fs.digest(&lf.path)? {
+                        adb_with_reason(
+                            adb_command, af, &lf.path, "HASH", setmtime, verify, src_fs, dest_fs,
+                        )?
+                    }
                 }
                 Some(_) => (), //log!("SKIP: '{}'", af.path.display()),
                 None => adb_with_reason(
@@ -171,6 +226,8 @@ fn pull_push<SRC: FileSystem, DEST: FileSystem>(
                     &dest.join(&path).join(&*af.name),
                     "DNE",
                     setmtime,
+                    verify,
+                    src_fs,
                     dest_fs,
                 )?,
             }
@@ -223,19 +280,169 @@ fn pull_push<SRC: FileSystem, DEST: FileSystem>(
     Ok(())
 }
 
+fn native_to_unix(p: &Path) -> UnixPathBuf {
+    typed_path::PathBuf::<typed_path::NativeEncoding>::try_from(p.to_path_buf())
+        .unwrap()
+        .with_unix_encoding()
+}
+
+fn local_mtime(md: &std::fs::Metadata) -> i64 {
+    md.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Mirror one local path that changed on disk to its place on the device,
+/// reusing the same size/timestamp comparison and `delete_if_dne` semantics as
+/// the one-shot `push`, but for a single path instead of the whole tree.
+fn mirror_path(
+    android_fs: &mut AndroidFS,
+    local: &Path,
+    source_root: &Path,
+    dest_unix: &UnixPath,
+    args: &PullPushArgs,
+) -> anyhow::Result<()> {
+    // `notify` hands us absolute paths; resolve `local` the same way
+    // `source_root` was resolved so the prefix strip is reliable. A path
+    // outside the watched tree is a bug, not something to silently remap.
+    let local_abs = local.canonicalize().unwrap_or_else(|_| local.to_path_buf());
+    let rel = local_abs.strip_prefix(source_root).map_err(|_| {
+        anyhow::anyhow!("event path '{}' is outside '{}'", local.display(), source_root.display())
+    })?;
+    let remote = dest_unix.join(native_to_unix(rel));
+    if local.exists() {
+        let md = std::fs::metadata(local)?;
+        if md.is_dir() {
+            android_fs.mkdir(&remote)?;
+            return Ok(());
+        }
+        let local_ts = local_mtime(&md);
+        // Without a sync connection, fall back to the shell `stat`.
+        let remote_st = match android_fs.sync.as_mut() {
+            Some(s) => s
+                .stat(remote.as_str())
+                .ok()
+                .filter(|st| st.exists())
+                .map(|st| (st.size as u64, st.mtime)),
+            None => android_fs.stat(&remote).ok().map(|f| (f.size, f.timestamp)),
+        };
+        let up_to_date = remote_st
+            .map(|(size, mtime)| size == md.len() && mtime >= local_ts)
+            .unwrap_or(false);
+        if up_to_date {
+            return Ok(());
+        }
+        if let Some(parent) = remote.parent() {
+            android_fs.mkdir(parent)?;
+        }
+        let op = android_fs.transfer("push", &native_to_unix(local), &remote, args.set_times)?;
+        if args.set_times {
+            android_fs.set_mtime(&remote, local_ts)?;
+        }
+        if args.verify {
+            let got = android_fs.stat(&remote)?;
+            if got.size != md.len() {
+                anyhow::bail!(
+                    "verify failed for '{}': size {} on device, expected {}",
+                    remote.display(),
+                    got.size,
+                    md.len()
+                );
+            }
+            if args.set_times && got.timestamp != local_ts {
+                anyhow::bail!(
+                    "verify failed for '{}': mtime {} on device, expected {}",
+                    remote.display(),
+                    got.timestamp,
+                    local_ts
+                );
+            }
+        }
+        log!("push (WATCH) {}", op.trim_end());
+    } else if args.delete_if_dne {
+        log!("DEL (WATCH): '{}'", remote.display());
+        // The local entry is already gone, so ask the device whether the
+        // target is a directory to pick the right removal.
+        let is_dir = match android_fs.sync.as_mut() {
+            Some(s) => s.stat(remote.as_str()).map(|st| st.is_dir()).unwrap_or(false),
+            None => android_fs
+                .shell
+                .exec(&format!(
+                    "[ -d {} ] && echo dir",
+                    adb_sink::adb::shell_quote(remote.as_str())
+                ))
+                .map(|out| out.trim_end() == "dir")
+                .unwrap_or(false),
+        };
+        let _ = if is_dir {
+            android_fs.rm_dir(&remote)
+        } else {
+            android_fs.rm_file(&remote)
+        };
+    }
+    Ok(())
+}
+
+/// Perform an initial `push` sync, then keep mirroring local changes to the
+/// device as the filesystem notifies us of them.
+fn watch(android_fs: &mut AndroidFS, args: PullPushArgs) -> anyhow::Result<()> {
+    use notify::{RecursiveMode, Watcher};
+
+    pull_push::<LocalFS, AndroidFS>(&mut LocalFS, android_fs, args.clone(), "push")?;
+
+    let source_file_name = args.source.file_name().unwrap().to_str().unwrap().to_string();
+    let mut dest_unix = native_to_unix(&args.dest);
+    dest_unix.push(&source_file_name);
+
+    // Resolve the watch root once so event paths (which `notify` reports
+    // absolute) strip cleanly against it.
+    let source_root = args
+        .source
+        .canonicalize()
+        .unwrap_or_else(|_| args.source.clone());
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(&args.source, RecursiveMode::Recursive)?;
+    log!("watching '{}' for changes ...", args.source.display());
+
+    // Coalesce bursts: block for the first event, then keep draining until the
+    // stream goes quiet for `DEBOUNCE`.
+    const DEBOUNCE: Duration = Duration::from_millis(300);
+    loop {
+        let mut changed: HashSet<PathBuf> = HashSet::new();
+        if let Ok(res) = rx.recv() {
+            changed.extend(res.into_iter().flat_map(|e| e.paths));
+        } else {
+            break;
+        }
+        while let Ok(res) = rx.recv_timeout(DEBOUNCE) {
+            changed.extend(res.into_iter().flat_map(|e| e.paths));
+        }
+        for p in changed {
+            if let Err(e) = mirror_path(android_fs, &p, &source_root, &dest_unix, &args) {
+                log!("watch: could not mirror '{}': {e}", p.display());
+            }
+        }
+    }
+    Ok(())
+}
+
 fn run() -> anyhow::Result<()> {
     let args = Cli::parse();
+    let mut serials = Vec::new();
     match adb_cmd!("devices") {
         Ok(devices) => {
             println!("{}\n", devices.trim());
-            if devices
+            serials = devices
                 .lines()
-                .filter(|line| line.contains("\tdevice"))
-                .count()
-                > 1
-            {
-                anyhow::bail!("more than 1 device connected");
-            }
+                .filter_map(|line| line.strip_suffix("\tdevice"))
+                .map(|s| s.to_string())
+                .collect();
         }
         Err(AdbErr::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
             anyhow::bail!("adb binary not found")
@@ -243,8 +450,33 @@ fn run() -> anyhow::Result<()> {
         Err(e) => anyhow::bail!("{}", e),
     }
 
+    // Resolve which device to target, mirroring `adb`'s own disambiguation.
+    let serial = match args.serial {
+        Some(s) => {
+            if !serials.iter().any(|d| d == &s) {
+                anyhow::bail!("no such device: '{s}'");
+            }
+            s
+        }
+        None if serials.len() > 1 => {
+            anyhow::bail!(
+                "more than 1 device connected, pass -s <serial>:\n{}",
+                serials.join("\n")
+            );
+        }
+        None => match serials.into_iter().next() {
+            Some(s) => s,
+            None => anyhow::bail!("no devices connected"),
+        },
+    };
+    DeviceSerial(Some(serial.clone())).install();
+
+    // Prefer the in-process sync protocol; fall back to the adb binary if the
+    // server cannot be reached.
+    let sync = adb_sink::adb::SyncClient::connect(&serial).ok();
     let mut android_fs = AndroidFS {
         shell: AdbShell::new()?,
+        sync,
     };
     match args.subcmd {
         SubCmds::Pull(p) => {
@@ -253,6 +485,7 @@ fn run() -> anyhow::Result<()> {
         SubCmds::Push(p) => {
             pull_push::<LocalFS, AndroidFS>(&mut LocalFS, &mut android_fs, p, "push")?
         }
+        SubCmds::Watch(p) => watch(&mut android_fs, p)?,
     }
     Ok(())
 }